@@ -1,20 +1,63 @@
+use brotli::CompressorWriter;
+use bytes::Bytes;
 use deflate::write::GzEncoder;
 use deflate::CompressionOptions;
 use futures::future;
-use http::{self, header, StatusCode};
+use http::{self, header, Method, StatusCode};
 use hyper::{service::Service, Body, Request, Response};
+use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt;
+use std::future::Future;
 use std::io::{self, Write};
+use std::pin::Pin;
 use std::task::{Context, Poll};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
 use tracing::{error, trace};
 
 use super::FmtMetrics;
 
+/// The number of compressed chunks that may be buffered between the
+/// blocking render/compress task and the response body stream before the
+/// renderer blocks waiting for the client to catch up.
+const CHANNEL_CAPACITY: usize = 4;
+
+/// The default minimum body size, in bytes, below which compression is
+/// skipped. Compressing a handful of metric lines costs more in CPU than it
+/// saves in bytes on the wire.
+const DEFAULT_MIN_COMPRESS_BYTES: usize = 1024;
+
 /// Serve Prometheues metrics.
 #[derive(Debug, Clone)]
 pub struct Serve<M: FmtMetrics> {
     metrics: M,
+    codecs: Codecs,
+    min_compress_bytes: usize,
+}
+
+/// The optional content-codings a [`Serve`] may use when responding to a
+/// request, beyond the always-available `gzip` and `identity`.
+#[derive(Debug, Clone, Copy)]
+pub struct Codecs {
+    /// Enables Brotli compression. Brotli is more CPU-intensive than gzip
+    /// but typically yields smaller output for the repetitive label text
+    /// Prometheus exposition produces.
+    pub brotli: bool,
+}
+
+impl Default for Codecs {
+    fn default() -> Self {
+        Self { brotli: true }
+    }
+}
+
+/// A content-coding this server knows how to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coding {
+    Brotli,
+    Gzip,
+    Identity,
 }
 
 #[derive(Debug)]
@@ -23,31 +66,383 @@ enum ServeError {
     Io(io::Error),
 }
 
+/// An `io::Write` that forwards each write as a chunk over an mpsc channel,
+/// so a compressor can be driven from a blocking task while the chunks it
+/// produces are streamed into the response body as they're ready.
+struct ChunkWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "response body was dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Wraps a downstream writer `W` with whichever encoder a negotiated
+/// [`Coding`] calls for, so callers that have already chosen a coding don't
+/// need to match on it at every write.
+enum Encoder<W: Write> {
+    Brotli(CompressorWriter<W>),
+    Gzip(GzEncoder<W>),
+    Identity(W),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(coding: Coding, sink: W) -> Self {
+        match coding {
+            Coding::Brotli => Self::Brotli(CompressorWriter::new(sink, 4096, 5, 22)),
+            Coding::Gzip => Self::Gzip(GzEncoder::new(sink, CompressionOptions::fast())),
+            Coding::Identity => Self::Identity(sink),
+        }
+    }
+
+    /// Flushes any buffered output and finalizes the encoding (e.g. writing
+    /// gzip's trailing CRC), consuming `self`.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Brotli(mut w) => w.flush(),
+            Self::Gzip(w) => w.finish().map(drop),
+            Self::Identity(mut w) => w.flush(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Brotli(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            Self::Identity(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Brotli(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            Self::Identity(w) => w.flush(),
+        }
+    }
+}
+
+enum GateState<W: Write> {
+    /// Still below the size threshold: output is held in `buf` rather than
+    /// being committed to a coding, since a short render should end up
+    /// uncompressed.
+    Buffering { buf: Vec<u8>, sink: W },
+    /// The threshold has been crossed (or the render has finished) and a
+    /// coding has been chosen; all further writes go through it.
+    Chosen(Encoder<W>),
+}
+
+/// An `io::Write` that defers choosing a [`Coding`] until it has seen at
+/// least `threshold` bytes of output, so that a response whose rendered
+/// length turns out to be small is never compressed, while a response that
+/// exceeds the threshold is compressed without first being buffered in
+/// full. The chosen coding is reported over `decision` as soon as it's
+/// known, so a caller can finish building response headers before the rest
+/// of the body is written.
+struct ThresholdGate<W: Write> {
+    // `None` only transiently, while `write` or `finish` is transitioning
+    // out of `GateState::Buffering`.
+    state: Option<GateState<W>>,
+    threshold: usize,
+    coding: Coding,
+    identity_acceptable: bool,
+    decision: Option<oneshot::Sender<Coding>>,
+}
+
+impl<W: Write> ThresholdGate<W> {
+    fn new(
+        sink: W,
+        threshold: usize,
+        coding: Coding,
+        identity_acceptable: bool,
+        decision: oneshot::Sender<Coding>,
+    ) -> Self {
+        Self {
+            state: Some(GateState::Buffering {
+                buf: Vec::new(),
+                sink,
+            }),
+            threshold,
+            coding,
+            identity_acceptable,
+            decision: Some(decision),
+        }
+    }
+
+    /// Commits to `coding`, sends it over `self.decision`, and sets
+    /// `self.state` to an [`Encoder`] primed with whatever was buffered so
+    /// far.
+    fn choose(&mut self, coding: Coding, buf: Vec<u8>, sink: W) -> io::Result<()> {
+        // The receiving end may already be gone if the request was aborted
+        // before negotiation completed; that's surfaced when the encoder
+        // itself tries to write to `sink`, so a failed send here is ignored.
+        if let Some(decision) = self.decision.take() {
+            let _ = decision.send(coding);
+        }
+        let mut encoder = Encoder::new(coding, sink);
+        encoder.write_all(&buf)?;
+        self.state = Some(GateState::Chosen(encoder));
+        Ok(())
+    }
+
+    /// Flushes and finalizes the chosen encoder, choosing one first if the
+    /// render finished without ever crossing the threshold.
+    fn finish(mut self) -> io::Result<()> {
+        match self.state.take() {
+            Some(GateState::Buffering { buf, sink }) => {
+                // The whole body fit under the threshold: uncompressed,
+                // unless the client has explicitly ruled that out.
+                let coding = if self.identity_acceptable {
+                    Coding::Identity
+                } else {
+                    self.coding
+                };
+                self.choose(coding, buf, sink)?;
+            }
+            chosen @ Some(GateState::Chosen(_)) => self.state = chosen,
+            None => unreachable!("state is only None transiently within a single call"),
+        }
+        match self.state.take() {
+            Some(GateState::Chosen(encoder)) => encoder.finish(),
+            _ => unreachable!("choose always leaves GateState::Chosen behind"),
+        }
+    }
+}
+
+impl<W: Write> Write for ThresholdGate<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.state.take() {
+            Some(GateState::Buffering { buf: mut pending, sink }) => {
+                pending.extend_from_slice(buf);
+                if pending.len() >= self.threshold {
+                    // The threshold is crossed: the negotiated coding
+                    // applies regardless of `identity_acceptable`, since the
+                    // response is no longer small enough for the threshold
+                    // to excuse it.
+                    let coding = self.coding;
+                    self.choose(coding, pending, sink)?;
+                } else {
+                    self.state = Some(GateState::Buffering { buf: pending, sink });
+                }
+                Ok(buf.len())
+            }
+            Some(GateState::Chosen(mut encoder)) => {
+                let n = encoder.write(buf)?;
+                self.state = Some(GateState::Chosen(encoder));
+                Ok(n)
+            }
+            None => unreachable!("state is only None transiently within a single call"),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            Some(GateState::Buffering { .. }) | None => Ok(()),
+            Some(GateState::Chosen(encoder)) => encoder.flush(),
+        }
+    }
+}
+
 // ===== impl Serve =====
 
 impl<M: FmtMetrics> Serve<M> {
-    pub fn new(metrics: M) -> Self {
-        Self { metrics }
+    pub fn new(metrics: M, codecs: Codecs) -> Self {
+        Self {
+            metrics,
+            codecs,
+            min_compress_bytes: DEFAULT_MIN_COMPRESS_BYTES,
+        }
+    }
+
+    /// Overrides the minimum body size below which compression is skipped.
+    pub fn with_min_compress_bytes(mut self, min_compress_bytes: usize) -> Self {
+        self.min_compress_bytes = min_compress_bytes;
+        self
+    }
+
+    /// The codings this server is able to produce, in order from most to
+    /// least preferred. Used to break ties when a client's `Accept-Encoding`
+    /// header gives equal weight to more than one supported coding.
+    fn supported(&self) -> Vec<Coding> {
+        let mut codings = Vec::with_capacity(3);
+        if self.codecs.brotli {
+            codings.push(Coding::Brotli);
+        }
+        codings.push(Coding::Gzip);
+        codings.push(Coding::Identity);
+        codings
+    }
+
+    /// Negotiates a content-coding from the request's `Accept-Encoding`
+    /// header(s), returning `None` if no coding this server supports
+    /// (including `identity`) is acceptable to the client.
+    ///
+    /// This follows the negotiation algorithm described by RFC 7231 §5.3.4:
+    /// each header is parsed into `coding;q=value` pairs (`q` defaulting to
+    /// `1.0`), codings with an explicit `q=0` are forbidden, an unqualified
+    /// `*` supplies the qvalue for any coding not otherwise mentioned, and
+    /// `identity` is acceptable with `q=1.0` unless the header explicitly
+    /// says otherwise. The supported coding with the highest qvalue wins,
+    /// ties broken by `Self::supported`'s preference order.
+    fn negotiate<B>(&self, req: &Request<B>) -> Option<Coding> {
+        // No `Accept-Encoding` header at all means any coding is acceptable,
+        // but per the documented baseline behavior a request that doesn't
+        // ask for compression (e.g. a bare `curl`) should get the plaintext
+        // body rather than being surprised with a compressed one.
+        if !Self::has_accept_encoding(req) {
+            return Some(Coding::Identity);
+        }
+
+        let supported = self.supported();
+        let codings = Self::parse_accept_encoding(req);
+
+        supported
+            .iter()
+            .copied()
+            .map(|c| (c, Self::qvalue(&codings, c)))
+            .filter(|&(_, q)| q > 0.0)
+            .max_by(|&(ca, qa), &(cb, qb)| {
+                qa.partial_cmp(&qb).unwrap_or(Ordering::Equal).then_with(|| {
+                    let rank = |c: Coding| supported.iter().position(|&x| x == c).unwrap_or(0);
+                    rank(cb).cmp(&rank(ca))
+                })
+            })
+            .map(|(c, _)| c)
+    }
+
+    /// Returns whether `identity` is acceptable to the request's
+    /// `Accept-Encoding` header(s), following the same rules as `negotiate`.
+    ///
+    /// Used to decide whether the small-body threshold in `Service::call`
+    /// may substitute `identity` for a negotiated coding: if the client
+    /// explicitly forbade `identity` (e.g. `identity;q=0, gzip;q=1`), the
+    /// threshold must not override the negotiated coding.
+    fn identity_acceptable<B>(req: &Request<B>) -> bool {
+        if !Self::has_accept_encoding(req) {
+            return true;
+        }
+        Self::qvalue(&Self::parse_accept_encoding(req), Coding::Identity) > 0.0
     }
 
-    fn is_gzip<B>(req: &Request<B>) -> bool {
+    /// Whether the request carries at least one parseable `Accept-Encoding`
+    /// header value.
+    fn has_accept_encoding<B>(req: &Request<B>) -> bool {
         req.headers()
             .get_all(header::ACCEPT_ENCODING)
             .iter()
-            .any(|value| {
-                value
-                    .to_str()
-                    .ok()
-                    .map(|value| value.contains("gzip"))
-                    .unwrap_or(false)
-            })
+            .any(|v| v.to_str().is_ok())
+    }
+
+    /// Parses all of the request's `Accept-Encoding` header(s) into
+    /// `(coding, qvalue)` pairs.
+    fn parse_accept_encoding<B>(req: &Request<B>) -> Vec<(&str, f32)> {
+        req.headers()
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(Self::parse_codings)
+            .collect()
     }
+
+    /// Looks up `coding`'s qvalue among already-parsed `(coding, qvalue)`
+    /// pairs: an explicit match, else the `*` qvalue, else `1.0` for
+    /// `identity` (which RFC 7231 treats as acceptable by default) or `0.0`
+    /// for anything else.
+    fn qvalue(codings: &[(&str, f32)], coding: Coding) -> f32 {
+        let name = coding.as_str();
+        if let Some(&(_, q)) = codings.iter().find(|(c, _)| c.eq_ignore_ascii_case(name)) {
+            return q;
+        }
+        if let Some(&(_, q)) = codings.iter().find(|(c, _)| *c == "*") {
+            return q;
+        }
+        if coding == Coding::Identity {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Compresses `buf` with the given coding, returning the complete
+    /// encoded body. Used for `HEAD` requests, where the full
+    /// `Content-Length` must be known up front since no body is sent.
+    fn compress(coding: Coding, buf: &[u8]) -> io::Result<Vec<u8>> {
+        match coding {
+            Coding::Brotli => {
+                let mut writer = CompressorWriter::new(Vec::<u8>::new(), 4096, 5, 22);
+                writer.write_all(buf).and_then(|_| writer.flush())?;
+                Ok(writer.into_inner())
+            }
+            Coding::Gzip => {
+                let mut writer = GzEncoder::new(Vec::<u8>::new(), CompressionOptions::fast());
+                writer.write_all(buf)?;
+                writer.finish()
+            }
+            Coding::Identity => Ok(buf.to_vec()),
+        }
+    }
+
+    /// Parses an `Accept-Encoding` header value into `(coding, qvalue)`
+    /// pairs, e.g. `"gzip;q=0.8, identity;q=0.5, *;q=0"`.
+    fn parse_codings(value: &str) -> impl Iterator<Item = (&str, f32)> {
+        value.split(',').filter_map(|item| {
+            let item = item.trim();
+            if item.is_empty() {
+                return None;
+            }
+            let mut parts = item.splitn(2, ';');
+            let coding = parts.next()?.trim();
+            let q = parts
+                .next()
+                .and_then(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, q))
+        })
+    }
+}
+
+impl Coding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+            Coding::Identity => "identity",
+        }
+    }
+
+    /// The `Content-Encoding` header value for this coding, or `None` for
+    /// `identity`, which is not announced as a `Content-Encoding`.
+    fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Coding::Identity => None,
+            coding => Some(coding.as_str()),
+        }
+    }
+}
+
+fn internal_error_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::empty())
+        .expect("builder with known status code should not fail")
 }
 
-impl<M: FmtMetrics> Service<Request<Body>> for Serve<M> {
+impl<M: FmtMetrics + Clone + Send + 'static> Service<Request<Body>> for Serve<M> {
     type Response = Response<Body>;
     type Error = io::Error;
-    type Future = future::Ready<Result<Response<Body>, Self::Error>>;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
@@ -59,42 +454,156 @@ impl<M: FmtMetrics> Service<Request<Body>> for Serve<M> {
                 .status(StatusCode::NOT_FOUND)
                 .body(Body::empty())
                 .expect("builder with known status code should not fail");
-            return future::ok(rsp);
-        }
-
-        let resp = if Self::is_gzip(&req) {
-            trace!("gzipping metrics");
-            let mut writer = GzEncoder::new(Vec::<u8>::new(), CompressionOptions::fast());
-            write!(&mut writer, "{}", self.metrics.as_display())
-                .and_then(|_| writer.finish())
-                .map_err(ServeError::from)
-                .and_then(|body| {
-                    Response::builder()
-                        .header(header::CONTENT_ENCODING, "gzip")
-                        .header(header::CONTENT_TYPE, "text/plain")
-                        .body(Body::from(body))
-                        .map_err(ServeError::from)
-                })
-        } else {
-            let mut writer = Vec::<u8>::new();
-            write!(&mut writer, "{}", self.metrics.as_display())
-                .map_err(ServeError::from)
-                .and_then(|_| {
-                    Response::builder()
-                        .header(header::CONTENT_TYPE, "text/plain")
-                        .body(Body::from(writer))
-                        .map_err(ServeError::from)
-                })
-        };
+            return Box::pin(future::ok(rsp));
+        }
 
-        let resp = resp.unwrap_or_else(|e| {
-            error!("{}", e);
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let rsp = Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, "GET, HEAD")
                 .body(Body::empty())
-                .expect("builder with known status code should not fail")
+                .expect("builder with known status code should not fail");
+            return Box::pin(future::ok(rsp));
+        }
+
+        let coding = match self.negotiate(&req) {
+            Some(coding) => coding,
+            None => {
+                trace!("no acceptable content-coding for /metrics request");
+                let rsp = Response::builder()
+                    .status(StatusCode::NOT_ACCEPTABLE)
+                    .body(Body::empty())
+                    .expect("builder with known status code should not fail");
+                return Box::pin(future::ok(rsp));
+            }
+        };
+
+        let identity_acceptable = Self::identity_acceptable(&req);
+        let metrics = self.metrics.clone();
+        let min_compress_bytes = self.min_compress_bytes;
+
+        // `HEAD` must report the full `Content-Length` up front, which
+        // means compressing (or at least rendering) the entire body before
+        // any response can be sent — there's no way around buffering it in
+        // full. `GET`, with no such requirement, streams the render through
+        // `ThresholdGate` below so that only the first `min_compress_bytes`
+        // or so are ever held in memory at once, restoring the bounded peak
+        // memory this module is meant to provide for the common case of a
+        // large registry scraped with `GET`.
+        //
+        // Both branches render and compress on a `spawn_blocking` task
+        // rather than inline: rendering and compressing a large registry is
+        // CPU-heavy enough to block the executor if run directly in the
+        // async `call` future.
+        if req.method() == Method::HEAD {
+            return Box::pin(async move {
+                let buf = match tokio::task::spawn_blocking(move || {
+                    let mut buf = Vec::new();
+                    write!(&mut buf, "{}", metrics.as_display())?;
+                    Ok::<_, io::Error>(buf)
+                })
+                .await
+                {
+                    Ok(Ok(buf)) => buf,
+                    Ok(Err(e)) => {
+                        error!("{}", ServeError::from(e));
+                        return Ok(internal_error_response());
+                    }
+                    Err(e) => {
+                        error!("metrics render task panicked: {}", e);
+                        return Ok(internal_error_response());
+                    }
+                };
+
+                // Below the threshold, compression isn't worth the CPU
+                // cost, but this may only substitute `identity` for the
+                // negotiated coding when the client hasn't explicitly
+                // forbidden `identity` itself.
+                let coding = if buf.len() < min_compress_bytes && identity_acceptable {
+                    Coding::Identity
+                } else {
+                    coding
+                };
+
+                let rsp = match tokio::task::spawn_blocking(move || Self::compress(coding, &buf))
+                    .await
+                {
+                    Ok(Ok(body)) => {
+                        let mut builder = Response::builder()
+                            .header(header::CONTENT_TYPE, "text/plain")
+                            .header(header::VARY, header::ACCEPT_ENCODING.as_str())
+                            .header(header::CONTENT_LENGTH, body.len());
+                        if let Some(encoding) = coding.content_encoding() {
+                            builder = builder.header(header::CONTENT_ENCODING, encoding);
+                        }
+                        builder
+                            .body(Body::empty())
+                            .expect("builder with known status code should not fail")
+                    }
+                    Ok(Err(e)) => {
+                        error!("{}", ServeError::from(e));
+                        internal_error_response()
+                    }
+                    Err(e) => {
+                        error!("metrics compress task panicked: {}", e);
+                        internal_error_response()
+                    }
+                };
+                Ok(rsp)
+            });
+        }
+
+        let (chunk_tx, chunk_rx) = mpsc::channel::<io::Result<Bytes>>(CHANNEL_CAPACITY);
+        let (decision_tx, decision_rx) = oneshot::channel::<Coding>();
+        tokio::task::spawn_blocking(move || {
+            let writer = ChunkWriter { tx: chunk_tx };
+            let mut gate = ThresholdGate::new(
+                writer,
+                min_compress_bytes,
+                coding,
+                identity_acceptable,
+                decision_tx,
+            );
+            let result =
+                write!(&mut gate, "{}", metrics.as_display()).and_then(|_| gate.finish());
+            if let Err(e) = result {
+                // A client that disconnects mid-scrape (or issues a
+                // `Range`/early-close request) is routine, not a server
+                // problem, and would otherwise spam error-level logs.
+                if e.kind() == io::ErrorKind::BrokenPipe {
+                    trace!("client disconnected while streaming metrics: {}", e);
+                } else {
+                    error!("error writing metrics response: {}", e);
+                }
+            }
         });
-        future::ok(resp)
+
+        Box::pin(async move {
+            let coding = match decision_rx.await {
+                Ok(coding) => coding,
+                Err(_) => {
+                    error!("metrics render task exited before choosing a content-coding");
+                    return Ok(internal_error_response());
+                }
+            };
+
+            // Always advertise that the response varies by `Accept-Encoding`,
+            // so caches don't serve a compressed body to a client that can't
+            // decode it (or vice versa).
+            let mut builder = Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain")
+                .header(header::VARY, header::ACCEPT_ENCODING.as_str());
+            if let Some(encoding) = coding.content_encoding() {
+                builder = builder.header(header::CONTENT_ENCODING, encoding);
+            }
+            let resp = builder
+                .body(Body::wrap_stream(ReceiverStream::new(chunk_rx)))
+                .unwrap_or_else(|e| {
+                    error!("{}", ServeError::from(e));
+                    internal_error_response()
+                });
+            Ok(resp)
+        })
     }
 }
 
@@ -129,3 +638,165 @@ impl Error for ServeError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    #[derive(Clone)]
+    struct NoopMetrics;
+
+    impl FmtMetrics for NoopMetrics {
+        fn fmt_metrics(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "")
+        }
+    }
+
+    /// Renders `line\n` repeated `repeat` times, so tests can produce a body
+    /// that's small (`repeat: 1`) or comfortably past any size threshold.
+    #[derive(Clone)]
+    struct RepeatingMetrics {
+        line: &'static str,
+        repeat: usize,
+    }
+
+    impl FmtMetrics for RepeatingMetrics {
+        fn fmt_metrics(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for _ in 0..self.repeat {
+                writeln!(f, "{}", self.line)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn serve(codecs: Codecs) -> Serve<NoopMetrics> {
+        Serve::new(NoopMetrics, codecs)
+    }
+
+    fn req(accept_encoding: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder();
+        if let Some(value) = accept_encoding {
+            builder = builder.header(header::ACCEPT_ENCODING, value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn metrics_req(method: Method, accept_encoding: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri("/metrics");
+        if let Some(value) = accept_encoding {
+            builder = builder.header(header::ACCEPT_ENCODING, value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn parse_codings_parses_qvalues() {
+        let parsed =
+            Serve::<NoopMetrics>::parse_codings("gzip;q=0.8, identity;q=0.5, *;q=0")
+                .collect::<Vec<_>>();
+        assert_eq!(parsed, vec![("gzip", 0.8), ("identity", 0.5), ("*", 0.0)]);
+    }
+
+    #[test]
+    fn negotiate_defaults_to_identity_without_header() {
+        let s = serve(Codecs::default());
+        assert_eq!(s.negotiate(&req(None)), Some(Coding::Identity));
+    }
+
+    #[test]
+    fn negotiate_forbids_explicit_q0() {
+        let s = serve(Codecs { brotli: false });
+        assert_eq!(
+            s.negotiate(&req(Some("gzip;q=0"))),
+            Some(Coding::Identity)
+        );
+    }
+
+    #[test]
+    fn negotiate_wildcard_and_identity_q0_is_not_acceptable() {
+        let s = serve(Codecs::default());
+        assert_eq!(s.negotiate(&req(Some("*;q=0, identity;q=0"))), None);
+    }
+
+    #[test]
+    fn negotiate_breaks_ties_by_preference_order() {
+        let s = serve(Codecs::default());
+        assert_eq!(
+            s.negotiate(&req(Some("gzip;q=0.9, br;q=0.9, identity;q=0.5"))),
+            Some(Coding::Brotli)
+        );
+    }
+
+    #[tokio::test]
+    async fn get_past_threshold_is_brotli_compressed() {
+        let metrics = RepeatingMetrics {
+            line: "metric_total 1",
+            repeat: 200,
+        };
+        let rendered = format!("{}\n", metrics.line).repeat(metrics.repeat);
+        let mut s = Serve::new(metrics, Codecs::default()).with_min_compress_bytes(0);
+
+        let rsp = s
+            .call(metrics_req(Method::GET, Some("br")))
+            .await
+            .unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(rsp.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        let mut decompressed = Vec::new();
+        brotli::Decompressor::new(Cursor::new(body.as_ref()), 4096)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, rendered.into_bytes());
+    }
+
+    #[tokio::test]
+    async fn get_below_threshold_skips_compression_but_still_varies() {
+        let mut s = Serve::new(NoopMetrics, Codecs::default());
+
+        let rsp = s
+            .call(metrics_req(Method::GET, Some("br")))
+            .await
+            .unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert!(rsp.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(rsp.headers().get(header::VARY).unwrap(), "accept-encoding");
+    }
+
+    #[tokio::test]
+    async fn head_reports_length_with_empty_body() {
+        let metrics = RepeatingMetrics {
+            line: "x",
+            repeat: 1,
+        };
+        let rendered = format!("{}\n", metrics.line);
+        let mut s = Serve::new(metrics, Codecs::default());
+
+        let rsp = s.call(metrics_req(Method::HEAD, None)).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert!(rsp.headers().get(header::CONTENT_ENCODING).is_none());
+        let content_length: usize = rsp
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(content_length, rendered.len());
+
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_get_head_is_rejected_with_405() {
+        let mut s = serve(Codecs::default());
+
+        let rsp = s.call(metrics_req(Method::POST, None)).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(rsp.headers().get(header::ALLOW).unwrap(), "GET, HEAD");
+    }
+}